@@ -0,0 +1,107 @@
+//! The SASL handshake (`AUTH EXTERNAL`, `NEGOTIATE_UNIX_FD`, `BEGIN`) that brings a freshly
+//! connected `Transport` up to the point where D-Bus messages can be exchanged. `client_conn`'s
+//! `handshake` calls these in sequence, giving each one whatever time is left of the caller's
+//! overall connect deadline via `calc_timeout_left`; every blocking read/write here is bounded by
+//! that `timeout` the same way `refill_buffer` bounds its `recvmsg` calls.
+
+use crate::client_conn::Error;
+use crate::client_conn::Result;
+use crate::client_conn::Transport;
+use std::io::Read;
+use std::io::Write;
+use std::time::Duration;
+
+/// Outcome of a single SASL auth step.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum AuthResult {
+    Ok,
+    Rejected,
+}
+
+/// Sends `AUTH EXTERNAL <hex-encoded-uid>` and reads the server's `OK <guid>`/`REJECTED` reply.
+pub(crate) fn do_auth(stream: &mut Transport, timeout: Option<Duration>) -> Result<AuthResult> {
+    write_line(stream, timeout, &format!("\0AUTH EXTERNAL {}", hex_uid()))?;
+    let line = read_line(stream, timeout)?;
+    Ok(if line.starts_with("OK ") {
+        AuthResult::Ok
+    } else {
+        AuthResult::Rejected
+    })
+}
+
+/// Sends `NEGOTIATE_UNIX_FD` and reads the server's `AGREE_UNIX_FD`/`ERROR` reply.
+pub(crate) fn negotiate_unix_fds(stream: &mut Transport, timeout: Option<Duration>) -> Result<AuthResult> {
+    write_line(stream, timeout, "NEGOTIATE_UNIX_FD")?;
+    let line = read_line(stream, timeout)?;
+    Ok(if line == "AGREE_UNIX_FD" {
+        AuthResult::Ok
+    } else {
+        AuthResult::Rejected
+    })
+}
+
+/// Sends `BEGIN`, switching the connection from SASL text mode into the binary D-Bus protocol.
+/// There is no reply to wait for, so only the write itself is bounded by `timeout`.
+pub(crate) fn send_begin(stream: &mut Transport, timeout: Option<Duration>) -> Result<()> {
+    write_line(stream, timeout, "BEGIN")
+}
+
+fn hex_uid() -> String {
+    nix::unistd::getuid()
+        .as_raw()
+        .to_string()
+        .bytes()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Writes `line` followed by `\r\n`, bounding the write with `timeout` using the same
+/// save/set/restore dance `Conn::refill_buffer` uses around `recvmsg`.
+fn write_line(stream: &mut Transport, timeout: Option<Duration>, line: &str) -> Result<()> {
+    let old_timeout = stream.write_timeout()?;
+    stream.set_write_timeout(timeout)?;
+    let result = stream.write_all(format!("{}\r\n", line).as_bytes());
+    stream.set_write_timeout(old_timeout)?;
+    as_timed_out(result)?;
+    Ok(())
+}
+
+/// Reads a single `\r\n`-terminated line one byte at a time, bounding every underlying `read`
+/// call with `timeout` so a hung or silent peer can't stall the handshake forever.
+fn read_line(stream: &mut Transport, timeout: Option<Duration>) -> Result<String> {
+    let old_timeout = stream.read_timeout()?;
+    stream.set_read_timeout(timeout)?;
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    let result = (|| -> std::io::Result<()> {
+        loop {
+            stream.read_exact(&mut byte)?;
+            if byte[0] == b'\n' {
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return Ok(());
+            }
+            line.push(byte[0]);
+        }
+    })();
+    stream.set_read_timeout(old_timeout)?;
+    as_timed_out(result)?;
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// `set_read_timeout`/`set_write_timeout` make a blocking call return `WouldBlock` (or, on some
+/// platforms, `TimedOut`) once the deadline elapses; surface that the same way the rest of
+/// `Conn` surfaces an expired deadline, as `Error::TimedOut`, instead of a generic `IoError`.
+fn as_timed_out(result: std::io::Result<()>) -> Result<()> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(e)
+            if e.kind() == std::io::ErrorKind::WouldBlock
+                || e.kind() == std::io::ErrorKind::TimedOut =>
+        {
+            Err(Error::TimedOut)
+        }
+        Err(e) => Err(Error::from(e)),
+    }
+}
@@ -8,8 +8,13 @@ use crate::wire::unmarshal;
 use crate::wire::util;
 use std::collections::HashMap;
 use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
 use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
 use std::os::unix::io::RawFd;
 use std::os::unix::net::UnixStream;
 use std::path::PathBuf;
@@ -18,10 +23,65 @@ use std::time;
 use nix::cmsg_space;
 use nix::sys::socket::recvmsg;
 use nix::sys::socket::sendmsg;
+use nix::sys::socket::socket;
+use nix::sys::socket::AddressFamily;
 use nix::sys::socket::ControlMessage;
 use nix::sys::socket::ControlMessageOwned;
 use nix::sys::socket::MsgFlags;
+use nix::sys::socket::SockAddr;
+use nix::sys::socket::SockFlag;
+use nix::sys::socket::SockType;
+use nix::sys::socket::UnixAddr;
 use nix::sys::uio::IoVec;
+use nix::unistd::close;
+
+/// A file descriptor received via `SCM_RIGHTS` that owns the underlying fd: unless `into_raw_fd`
+/// is called to take it, the fd is closed when this value is dropped. `get_next_message` holds
+/// received fds in this type until the message passes the `UNIX_FDS` count check, so the fds of a
+/// message rejected with `FdCountMismatch` are closed. The fds of a message it returns are handed
+/// over as plain `RawFd`s in `raw_fds` and are not closed when that message is dropped.
+#[derive(Debug)]
+pub struct OwnedFd(Option<RawFd>);
+
+impl OwnedFd {
+    fn new(fd: RawFd) -> Self {
+        OwnedFd(Some(fd))
+    }
+
+    /// Borrow the raw fd without giving up ownership.
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.0.expect("OwnedFd used after into_raw_fd")
+    }
+
+    /// Take ownership of the raw fd. The caller is now responsible for closing it; `OwnedFd`
+    /// will no longer close it on drop.
+    pub fn into_raw_fd(mut self) -> RawFd {
+        self.0.take().expect("OwnedFd used after into_raw_fd")
+    }
+}
+
+impl Drop for OwnedFd {
+    fn drop(&mut self) {
+        if let Some(fd) = self.0.take() {
+            let _ = close(fd);
+        }
+    }
+}
+
+/// Implemented by message types that can carry fds received over the bus connection.
+/// `message::Message::raw_fds` is populated from `SCM_RIGHTS` by `Conn::get_next_message`; this
+/// trait gives `get_next_message` the fd count the message's header declares, which it checks
+/// against the fds queued by `refill_buffer` before handing them to the caller.
+pub trait RecvFd {
+    /// How many fds this message's `UNIX_FDS` header field declares it carries.
+    fn expected_fd_count(&self) -> u32;
+}
+
+/// Implemented by message types that can send fds over the bus connection.
+pub trait SendFd {
+    /// The fds to attach via `SCM_RIGHTS` when this message is sent.
+    fn fds_to_send(&self) -> &[RawFd];
+}
 
 /// Convenience wrapper around the lowlevel connection
 pub struct RpcConn {
@@ -30,6 +90,9 @@ pub struct RpcConn {
     responses: HashMap<u32, message::Message>,
     conn: Conn,
     filter: Box<MessageFilter>,
+
+    last_reconnect_generation: u64,
+    reconnect_hook: Option<Box<dyn FnMut(&mut Conn) -> Result<()>>>,
 }
 
 /// Filter out messages you dont want in your RpcConn.
@@ -76,8 +139,10 @@ impl RpcConn {
             signals: VecDeque::new(),
             calls: VecDeque::new(),
             responses: HashMap::new(),
+            last_reconnect_generation: conn.reconnect_generation,
             conn,
             filter: Box::new(|_| true),
+            reconnect_hook: None,
         }
     }
 
@@ -85,6 +150,26 @@ impl RpcConn {
         self.filter = filter;
     }
 
+    /// Set a hook that is run whenever the underlying [`Conn`] has transparently reconnected
+    /// (see [`Conn::set_auto_reconnect`]). Use this to re-send `Hello` and re-add any signal
+    /// match rules that the broken connection lost, since rustbus has no way to know about
+    /// those on its own.
+    pub fn set_reconnect_hook(&mut self, hook: Box<dyn FnMut(&mut Conn) -> Result<()>>) {
+        self.reconnect_hook = Some(hook);
+    }
+
+    /// Runs the reconnect hook if the connection has reconnected since we last checked.
+    fn check_reconnected(&mut self) -> Result<()> {
+        let current = self.conn.reconnect_generation();
+        if current != self.last_reconnect_generation {
+            self.last_reconnect_generation = current;
+            if let Some(hook) = &mut self.reconnect_hook {
+                hook(&mut self.conn)?;
+            }
+        }
+        Ok(())
+    }
+
     /// Return a response if one is there but dont block
     pub fn try_get_response(&mut self, serial: u32) -> Option<message::Message> {
         self.responses.remove(&serial)
@@ -140,7 +225,9 @@ impl RpcConn {
         msg: message::Message,
         timeout: Option<time::Duration>,
     ) -> Result<message::Message> {
-        self.conn.send_message(msg, timeout)
+        let reply = self.conn.send_message(msg, timeout)?;
+        self.check_reconnected()?;
+        Ok(reply)
     }
 
     /// This blocks until a new message (that should not be ignored) arrives.
@@ -151,6 +238,7 @@ impl RpcConn {
             let msg = self
                 .conn
                 .get_next_message(calc_timeout_left(&start_time, timeout)?)?;
+            self.check_reconnected()?;
 
             if self.filter.as_ref()(&msg) {
                 match msg.typ {
@@ -193,11 +281,314 @@ impl RpcConn {
     }
 }
 
+/// One entry of a (possibly semicolon-separated) D-Bus server/bus address, e.g. one item of
+/// `DBUS_SESSION_BUS_ADDRESS`. See the "Server Addresses" section of the D-Bus specification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BusAddress {
+    /// `unix:path=/run/user/1000/bus`
+    UnixPath(PathBuf),
+    /// `unix:abstract=/tmp/dbus-xyz` (Linux-only abstract-namespace socket)
+    UnixAbstract(Vec<u8>),
+    /// `tcp:host=...,port=...`
+    Tcp { host: String, port: u16 },
+    /// `nonce-tcp:host=...,port=...,noncefile=...`. The contents of `noncefile` must be sent
+    /// to the peer before the auth handshake starts.
+    NonceTcp {
+        host: String,
+        port: u16,
+        noncefile: PathBuf,
+    },
+}
+
+/// Parses a `DBUS_SESSION_BUS_ADDRESS`-style address list: a semicolon-separated list of
+/// `transport:key=value,key=value,...` entries, tried by the caller in order until one connects.
+pub fn parse_bus_addresses(addresses: &str) -> Result<Vec<BusAddress>> {
+    let mut parsed = Vec::new();
+    for entry in addresses.split(';').filter(|e| !e.is_empty()) {
+        let colon = entry
+            .find(':')
+            .ok_or_else(|| Error::AddressTypeNotSupported(entry.to_owned()))?;
+        let (transport, params) = (&entry[..colon], &entry[colon + 1..]);
+        let params = parse_address_params(params);
+        let addr = match transport {
+            "unix" => {
+                if let Some(path) = params.get("path") {
+                    BusAddress::UnixPath(PathBuf::from(path))
+                } else if let Some(name) = params.get("abstract") {
+                    BusAddress::UnixAbstract(name.as_bytes().to_vec())
+                } else {
+                    return Err(Error::AddressTypeNotSupported(entry.to_owned()));
+                }
+            }
+            "tcp" => BusAddress::Tcp {
+                host: params.get("host").cloned().unwrap_or_else(|| "localhost".to_owned()),
+                port: parse_port(&params, entry)?,
+            },
+            "nonce-tcp" => BusAddress::NonceTcp {
+                host: params.get("host").cloned().unwrap_or_else(|| "localhost".to_owned()),
+                port: parse_port(&params, entry)?,
+                noncefile: params
+                    .get("noncefile")
+                    .map(PathBuf::from)
+                    .ok_or_else(|| Error::AddressTypeNotSupported(entry.to_owned()))?,
+            },
+            _ => return Err(Error::AddressTypeNotSupported(entry.to_owned())),
+        };
+        parsed.push(addr);
+    }
+    if parsed.is_empty() {
+        return Err(Error::NoAdressFound);
+    }
+    Ok(parsed)
+}
+
+fn parse_port(params: &HashMap<String, String>, entry: &str) -> Result<u16> {
+    params
+        .get("port")
+        .and_then(|p| p.parse().ok())
+        .ok_or_else(|| Error::AddressTypeNotSupported(entry.to_owned()))
+}
+
+fn parse_address_params(params: &str) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+    for kv in params.split(',').filter(|kv| !kv.is_empty()) {
+        if let Some(eq) = kv.find('=') {
+            map.insert(kv[..eq].to_owned(), url_unescape(&kv[eq + 1..]));
+        }
+    }
+    map
+}
+
+/// D-Bus address values are percent-escaped (`%XX`); undo that. Works on raw bytes throughout
+/// (rather than slicing `s` by byte offset) so a stray `%` ahead of a multi-byte UTF-8 sequence
+/// can't land mid-character and panic; a `%` not followed by two hex digits is passed through
+/// unescaped instead of being treated as a parse error.
+fn url_unescape(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hi = (bytes[i + 1] as char).to_digit(16);
+            let lo = (bytes[i + 2] as char).to_digit(16);
+            if let (Some(hi), Some(lo)) = (hi, lo) {
+                out.push((hi * 16 + lo) as u8);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The actual byte-stream underlying a `Conn`. Unix-domain sockets (path or abstract-namespace)
+/// support passing file descriptors via `SCM_RIGHTS`; TCP sockets do not.
+#[derive(Debug)]
+pub(crate) enum Transport {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Transport {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Transport::Unix(s) => s.as_raw_fd(),
+            Transport::Tcp(s) => s.as_raw_fd(),
+        }
+    }
+
+    pub(crate) fn read_timeout(&self) -> std::io::Result<Option<time::Duration>> {
+        match self {
+            Transport::Unix(s) => s.read_timeout(),
+            Transport::Tcp(s) => s.read_timeout(),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<time::Duration>) -> std::io::Result<()> {
+        match self {
+            Transport::Unix(s) => s.set_read_timeout(timeout),
+            Transport::Tcp(s) => s.set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn write_timeout(&self) -> std::io::Result<Option<time::Duration>> {
+        match self {
+            Transport::Unix(s) => s.write_timeout(),
+            Transport::Tcp(s) => s.write_timeout(),
+        }
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<time::Duration>) -> std::io::Result<()> {
+        match self {
+            Transport::Unix(s) => s.set_write_timeout(timeout),
+            Transport::Tcp(s) => s.set_write_timeout(timeout),
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        match self {
+            Transport::Unix(s) => s.set_nonblocking(nonblocking),
+            Transport::Tcp(s) => s.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Transport::Unix(s) => s.read(buf),
+            Transport::Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Transport::Unix(s) => s.write(buf),
+            Transport::Tcp(s) => s.write(buf),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Transport::Unix(s) => s.flush(),
+            Transport::Tcp(s) => s.flush(),
+        }
+    }
+}
+
+/// Opens the raw stream for `addr`, without running the auth handshake. `timeout` bounds the
+/// connect itself (`None` blocks as long as the OS lets it).
+fn connect_transport(addr: &BusAddress, timeout: Option<time::Duration>) -> Result<Transport> {
+    match addr {
+        BusAddress::UnixPath(path) => Ok(Transport::Unix(connect_unix_with_timeout(
+            &SockAddr::Unix(UnixAddr::new(path)?),
+            timeout,
+        )?)),
+        BusAddress::UnixAbstract(name) => Ok(Transport::Unix(connect_unix_with_timeout(
+            &SockAddr::Unix(UnixAddr::new_abstract(name)?),
+            timeout,
+        )?)),
+        BusAddress::Tcp { host, port } => {
+            Ok(Transport::Tcp(connect_tcp_with_timeout(host, *port, timeout)?))
+        }
+        BusAddress::NonceTcp {
+            host,
+            port,
+            noncefile,
+        } => {
+            let mut stream = connect_tcp_with_timeout(host, *port, timeout)?;
+            let nonce = std::fs::read(noncefile)?;
+            stream.write_all(&nonce)?;
+            Ok(Transport::Tcp(stream))
+        }
+    }
+}
+
+/// Connects to `host:port`. With no timeout this is just `TcpStream::connect`, which already
+/// tries every address the host resolves to in turn. `TcpStream::connect_timeout` has no such
+/// multi-address variant, so with a timeout we resolve the addresses ourselves and try each in
+/// turn against the same overall deadline (via `calc_timeout_left`, the same pattern `refill`
+/// uses), so a multi-homed host with one unreachable address (e.g. a dead AAAA record) still
+/// connects over the next one instead of failing outright.
+fn connect_tcp_with_timeout(
+    host: &str,
+    port: u16,
+    timeout: Option<time::Duration>,
+) -> Result<TcpStream> {
+    let timeout = match timeout {
+        None => return Ok(TcpStream::connect((host, port))?),
+        Some(timeout) => timeout,
+    };
+
+    let start_time = time::Instant::now();
+    let mut last_err = None;
+    for addr in (host, port).to_socket_addrs()? {
+        let time_left = calc_timeout_left(&start_time, Some(timeout))?
+            .expect("calc_timeout_left(_, Some(_)) always returns Some");
+        match TcpStream::connect_timeout(&addr, time_left) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    match last_err {
+        Some(e) => Err(Error::from(e)),
+        None => Err(Error::AddressTypeNotSupported(format!("{}:{}", host, port))),
+    }
+}
+
+/// Connects a fresh unix-domain socket to `sockaddr`. With a timeout, the socket is connected
+/// non-blocking and we wait for it to become writable (or for the deadline) with `poll`, then
+/// check `SO_ERROR` to see whether the connect actually succeeded, mirroring the non-blocking
+/// connect dance `connect(2)` documents for stream sockets.
+fn connect_unix_with_timeout(
+    sockaddr: &SockAddr,
+    timeout: Option<time::Duration>,
+) -> Result<UnixStream> {
+    let timeout = match timeout {
+        None => {
+            let fd = socket(
+                AddressFamily::Unix,
+                SockType::Stream,
+                SockFlag::empty(),
+                None,
+            )?;
+            if let Err(e) = nix::sys::socket::connect(fd, sockaddr) {
+                let _ = close(fd);
+                return Err(Error::NixError(e));
+            }
+            // Safety: `fd` was just created above and is owned by nobody else yet.
+            return Ok(unsafe { UnixStream::from_raw_fd(fd) });
+        }
+        Some(timeout) => timeout,
+    };
+
+    let fd = socket(
+        AddressFamily::Unix,
+        SockType::Stream,
+        SockFlag::SOCK_NONBLOCK,
+        None,
+    )?;
+    match nix::sys::socket::connect(fd, sockaddr) {
+        Ok(()) => {}
+        Err(e) if e.as_errno() == Some(nix::errno::Errno::EINPROGRESS) => {
+            let mut poll_fds = [nix::poll::PollFd::new(fd, nix::poll::PollFlags::POLLOUT)];
+            let timeout_ms = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+            let ready = nix::poll::poll(&mut poll_fds, timeout_ms).map_err(|e| {
+                let _ = close(fd);
+                Error::NixError(e)
+            })?;
+            if ready == 0 {
+                let _ = close(fd);
+                return Err(Error::TimedOut);
+            }
+            let connect_err =
+                nix::sys::socket::getsockopt(fd, nix::sys::socket::sockopt::SocketError)
+                    .map_err(Error::NixError)?;
+            if connect_err != 0 {
+                let _ = close(fd);
+                return Err(Error::IoError(std::io::Error::from_raw_os_error(connect_err)));
+            }
+        }
+        Err(e) => {
+            let _ = close(fd);
+            return Err(Error::NixError(e));
+        }
+    }
+
+    // Safety: `fd` was just created above and is owned by nobody else yet.
+    let stream = unsafe { UnixStream::from_raw_fd(fd) };
+    stream.set_nonblocking(false)?;
+    Ok(stream)
+}
+
 /// A lowlevel abstraction over the raw unix socket
 #[derive(Debug)]
 pub struct Conn {
-    socket_path: PathBuf,
-    stream: UnixStream,
+    addr: BusAddress,
+    stream: Transport,
 
     byteorder: message::ByteOrder,
 
@@ -205,6 +596,67 @@ pub struct Conn {
     msg_buf_out: Vec<u8>,
 
     serial_counter: u32,
+
+    with_unix_fd: bool,
+    auto_reconnect: bool,
+    reconnect_generation: u64,
+
+    nonblocking: bool,
+    // ScmRights collected while assembling the message currently in msg_buf_in. Kept on the
+    // struct (instead of a local in get_next_message) so nothing is lost if a read spans
+    // several non-blocking calls.
+    pending_cmsgs: Vec<ControlMessageOwned>,
+
+    // State for a message that try_send_message started but could not finish writing in one
+    // non-blocking call.
+    out_write_cursor: usize,
+    out_pending_fds: Vec<RawFd>,
+    out_fds_sent: bool,
+    out_pending_serial: Option<u32>,
+
+    bytes_read_total: u64,
+    bytes_written_total: u64,
+    read_rate_avg: f64,
+    write_rate_avg: f64,
+    last_read_instant: Option<time::Instant>,
+    last_write_instant: Option<time::Instant>,
+
+    send_rate_limit: Option<u64>,
+
+    last_activity: time::Instant,
+    idle_trim_after: Option<time::Duration>,
+    idle_trim_retain: usize,
+}
+
+/// Snapshot of the throughput counters tracked by [`Conn::stats`].
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Exponential moving average of read throughput, in bytes/sec.
+    pub avg_read_bytes_per_sec: f64,
+    /// Exponential moving average of write throughput, in bytes/sec.
+    pub avg_write_bytes_per_sec: f64,
+}
+
+/// Weight given to the most recent sample in the throughput moving averages. Lower is smoother
+/// (slower to react), higher tracks bursts more closely.
+const THROUGHPUT_EMA_ALPHA: f64 = 0.2;
+
+fn update_throughput_avg(avg: &mut f64, elapsed: time::Duration, bytes: usize) {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        let instantaneous = bytes as f64 / secs;
+        *avg = THROUGHPUT_EMA_ALPHA * instantaneous + (1.0 - THROUGHPUT_EMA_ALPHA) * *avg;
+    }
+}
+
+/// Poll-style result for the non-blocking `try_*` methods on [`Conn`], for integration with an
+/// external event loop (e.g. mio/epoll) driven off `Conn`'s `AsRawFd` impl.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Async<T> {
+    Ready(T),
+    NotReady,
 }
 
 /// Errors that can occur when using the Conn/RpcConn
@@ -222,6 +674,19 @@ pub enum Error {
     NoAdressFound,
     UnexpectedTypeReceived,
     TimedOut,
+    OutputBufferFull,
+    /// A control message other than `SCM_RIGHTS` arrived on the bus connection. Rustbus has no
+    /// use for these (and no safe way to guess what to do with one), so it is a hard error
+    /// instead of being silently dropped.
+    UnsupportedControlMessage,
+    /// The number of fds actually attached to a message (via `SCM_RIGHTS`, on receive, or
+    /// `raw_fds`, on send) didn't match the message's `UNIX_FDS` header field.
+    FdCountMismatch { expected: u32, received: usize },
+    /// A message with one or more `raw_fds` was sent over a transport that can't carry them
+    /// (anything but a unix-domain socket; see `Transport::supports_fd_passing`). Sending it
+    /// anyway would silently drop the fds while the marshalled `UNIX_FDS` header still claims
+    /// them, desyncing whatever reads the message next.
+    FdPassingUnsupported,
 }
 
 impl std::convert::From<std::io::Error> for Error {
@@ -246,7 +711,54 @@ impl std::convert::From<nix::Error> for Error {
     }
 }
 
-type Result<T> = std::result::Result<T, Error>;
+pub(crate) type Result<T> = std::result::Result<T, Error>;
+
+/// Runs the auth handshake (SASL auth, optional unix-fd negotiation, `BEGIN`) over an
+/// already-connected transport. Unix-fd negotiation is skipped on transports that can't carry
+/// `SCM_RIGHTS` (e.g. TCP) even if the caller asked for it.
+///
+/// `timeout` is an overall deadline for the whole handshake, measured from `start_time`; each
+/// step gets whatever is left of it, via the same `calc_timeout_left` pattern `refill` uses.
+/// Pass `timeout: None` for no deadline.
+fn handshake(
+    stream: &mut Transport,
+    with_unix_fd: bool,
+    start_time: &time::Instant,
+    timeout: Option<time::Duration>,
+) -> Result<()> {
+    match auth::do_auth(stream, calc_timeout_left(start_time, timeout)?)? {
+        auth::AuthResult::Ok => {}
+        auth::AuthResult::Rejected => return Err(Error::AuthFailed),
+    }
+
+    if with_unix_fd && stream.supports_fd_passing() {
+        match auth::negotiate_unix_fds(stream, calc_timeout_left(start_time, timeout)?)? {
+            auth::AuthResult::Ok => {}
+            auth::AuthResult::Rejected => return Err(Error::UnixFdNegotiationFailed),
+        }
+    }
+
+    auth::send_begin(stream, calc_timeout_left(start_time, timeout)?)?;
+    Ok(())
+}
+
+impl Transport {
+    fn supports_fd_passing(&self) -> bool {
+        matches!(self, Transport::Unix(_))
+    }
+}
+
+impl RecvFd for message::Message {
+    fn expected_fd_count(&self) -> u32 {
+        self.num_unix_fds
+    }
+}
+
+impl SendFd for message::Message {
+    fn fds_to_send(&self) -> &[RawFd] {
+        &self.raw_fds
+    }
+}
 
 impl Conn {
     /// Connect to a unix socket and choose a byteorder
@@ -255,30 +767,249 @@ impl Conn {
         byteorder: message::ByteOrder,
         with_unix_fd: bool,
     ) -> Result<Conn> {
-        let mut stream = UnixStream::connect(&path)?;
-        match auth::do_auth(&mut stream)? {
-            auth::AuthResult::Ok => {}
-            auth::AuthResult::Rejected => return Err(Error::AuthFailed),
-        }
+        Self::connect_to_bus_addr(BusAddress::UnixPath(path), byteorder, with_unix_fd)
+    }
 
-        if with_unix_fd {
-            match auth::negotiate_unix_fds(&mut stream)? {
-                auth::AuthResult::Ok => {}
-                auth::AuthResult::Rejected => return Err(Error::UnixFdNegotiationFailed),
-            }
-        }
+    /// Connect to any supported D-Bus transport: a unix path/abstract socket, plain TCP, or
+    /// nonce-authenticated TCP. See `parse_bus_addresses`/`get_session_bus_addresses` for how to
+    /// obtain a `BusAddress` from `$DBUS_SESSION_BUS_ADDRESS`.
+    pub fn connect_to_bus_addr(
+        addr: BusAddress,
+        byteorder: message::ByteOrder,
+        with_unix_fd: bool,
+    ) -> Result<Conn> {
+        let start_time = time::Instant::now();
+        let mut stream = connect_transport(&addr, None)?;
+        handshake(&mut stream, with_unix_fd, &start_time, None)?;
+        Ok(Self::assemble(addr, stream, byteorder, with_unix_fd))
+    }
+
+    /// Like `connect_to_bus_with_byteorder`, but with a deadline covering the whole setup
+    /// sequence: the socket connect itself, then the auth handshake, unix-fd negotiation and
+    /// `BEGIN`. Returns `Error::TimedOut` if the budget runs out at any stage, the same way every
+    /// post-connect `Conn` method already can.
+    pub fn connect_to_bus_with_timeout(
+        path: PathBuf,
+        byteorder: message::ByteOrder,
+        with_unix_fd: bool,
+        timeout: Option<time::Duration>,
+    ) -> Result<Conn> {
+        Self::connect_to_bus_addr_with_timeout(
+            BusAddress::UnixPath(path),
+            byteorder,
+            with_unix_fd,
+            timeout,
+        )
+    }
 
-        auth::send_begin(&mut stream)?;
+    /// Like `connect_to_bus_addr`, but with an overall deadline; see
+    /// `connect_to_bus_with_timeout`.
+    pub fn connect_to_bus_addr_with_timeout(
+        addr: BusAddress,
+        byteorder: message::ByteOrder,
+        with_unix_fd: bool,
+        timeout: Option<time::Duration>,
+    ) -> Result<Conn> {
+        let start_time = time::Instant::now();
+        let mut stream = connect_transport(&addr, calc_timeout_left(&start_time, timeout)?)?;
+        handshake(&mut stream, with_unix_fd, &start_time, timeout)?;
+        Ok(Self::assemble(addr, stream, byteorder, with_unix_fd))
+    }
 
-        Ok(Conn {
-            socket_path: path,
+    fn assemble(
+        addr: BusAddress,
+        stream: Transport,
+        byteorder: message::ByteOrder,
+        with_unix_fd: bool,
+    ) -> Conn {
+        Conn {
+            addr,
             stream,
             msg_buf_in: Vec::new(),
             msg_buf_out: Vec::new(),
             byteorder,
 
             serial_counter: 1,
-        })
+
+            with_unix_fd,
+            auto_reconnect: false,
+            reconnect_generation: 0,
+
+            nonblocking: false,
+            pending_cmsgs: Vec::new(),
+
+            out_write_cursor: 0,
+            out_pending_fds: Vec::new(),
+            out_fds_sent: false,
+            out_pending_serial: None,
+
+            bytes_read_total: 0,
+            bytes_written_total: 0,
+            read_rate_avg: 0.0,
+            write_rate_avg: 0.0,
+            last_read_instant: None,
+            last_write_instant: None,
+
+            send_rate_limit: None,
+
+            last_activity: time::Instant::now(),
+            idle_trim_after: None,
+            idle_trim_retain: unmarshal::HEADER_LEN,
+        }
+    }
+
+    /// Configure idle-buffer trimming: if set, `msg_buf_in`/`msg_buf_out` are shrunk back down to
+    /// `retain_capacity` (floored at `unmarshal::HEADER_LEN`, so the common path never has to
+    /// reallocate from zero) the first time `get_next_message`/`send_message` run after at least
+    /// `idle_after` of inactivity. This bounds the memory a long-lived, mostly-idle connection
+    /// keeps pinned after handling one unusually large message. `None` (the default) disables
+    /// trimming.
+    pub fn set_idle_trim(&mut self, idle_after: Option<time::Duration>, retain_capacity: usize) {
+        self.idle_trim_after = idle_after;
+        self.idle_trim_retain = retain_capacity.max(unmarshal::HEADER_LEN);
+    }
+
+    /// Shrinks `msg_buf_in`/`msg_buf_out` down to the configured retained capacity. A no-op for
+    /// whichever buffer currently holds a partially-read or partially-written message, since
+    /// trimming must never happen while one is buffered.
+    pub fn trim_buffers(&mut self) {
+        if self.msg_buf_in.is_empty() && self.msg_buf_in.capacity() > self.idle_trim_retain {
+            // Vec has no stable "shrink to at least N" on the Rust edition this crate targets,
+            // so we just rebuild it; the buffer is empty here, so nothing is copied.
+            self.msg_buf_in = Vec::with_capacity(self.idle_trim_retain);
+        }
+        let out_pending = self.out_write_cursor < self.msg_buf_out.len();
+        if !out_pending && self.msg_buf_out.capacity() > self.idle_trim_retain {
+            self.msg_buf_out = Vec::with_capacity(self.idle_trim_retain);
+        }
+    }
+
+    fn touch_activity(&mut self) {
+        self.last_activity = time::Instant::now();
+    }
+
+    fn maybe_idle_trim(&mut self) {
+        if let Some(idle_after) = self.idle_trim_after {
+            if self.last_activity.elapsed() >= idle_after {
+                self.trim_buffers();
+            }
+        }
+        self.touch_activity();
+    }
+
+    /// Cumulative bytes transferred and a moving-average throughput, for logging/monitoring
+    /// transfer speed on this connection.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            bytes_read: self.bytes_read_total,
+            bytes_written: self.bytes_written_total,
+            avg_read_bytes_per_sec: self.read_rate_avg,
+            avg_write_bytes_per_sec: self.write_rate_avg,
+        }
+    }
+
+    /// Limit `send_message` to (approximately) `limit` bytes/sec by sleeping after each send for
+    /// however long the send was "ahead of schedule", smoothing bursty traffic on slow or shared
+    /// links. `None` (the default) disables the limiter.
+    ///
+    /// This only applies to the blocking `send_message` path. `try_send_message`/
+    /// `try_flush_message` never sleep the calling thread regardless of this setting, since doing
+    /// so would stall whatever event loop is driving them; the limiter is blocking-send-only.
+    pub fn set_send_rate_limit(&mut self, limit: Option<u64>) {
+        self.send_rate_limit = limit;
+    }
+
+    fn record_read(&mut self, n: usize) {
+        self.bytes_read_total += n as u64;
+        let now = time::Instant::now();
+        if let Some(last) = self.last_read_instant {
+            update_throughput_avg(&mut self.read_rate_avg, now.duration_since(last), n);
+        }
+        self.last_read_instant = Some(now);
+    }
+
+    fn record_write(&mut self, n: usize) {
+        self.bytes_written_total += n as u64;
+        let now = time::Instant::now();
+        if let Some(last) = self.last_write_instant {
+            update_throughput_avg(&mut self.write_rate_avg, now.duration_since(last), n);
+        }
+        self.last_write_instant = Some(now);
+    }
+
+    /// Put the underlying socket into (or take it out of) non-blocking mode, for use with
+    /// `try_get_next_message`/`try_send_message` from an external event loop. `Conn` also
+    /// implements `AsRawFd` so the socket can be registered with that event loop directly.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> Result<()> {
+        self.stream.set_nonblocking(nonblocking)?;
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+
+    /// Enable or disable transparent reconnection. When enabled, if `get_next_message` or
+    /// `send_message` detect that the peer has gone away (broken pipe / connection reset),
+    /// the socket is reopened, the handshake (auth, unix-fd negotiation, `BEGIN`) is replayed,
+    /// and the failed call is retried once against the new connection.
+    ///
+    /// This is opt-in because a reconnect silently loses any bus-side state (e.g. the unique
+    /// name from `Hello`, signal match rules), which only the caller can restore; see
+    /// [`RpcConn::set_reconnect_hook`].
+    pub fn set_auto_reconnect(&mut self, enabled: bool) {
+        self.auto_reconnect = enabled;
+    }
+
+    /// Bumped every time `reconnect` succeeds. Used by `RpcConn` to notice that a reconnect
+    /// happened so it can run its reconnect hook.
+    pub fn reconnect_generation(&self) -> u64 {
+        self.reconnect_generation
+    }
+
+    /// Reopen the connection to `addr` and replay the handshake, bounded by `timeout` across the
+    /// whole reconnect + handshake sequence (see `connect_to_bus_addr_with_timeout`). Any
+    /// partially-read or partially-written message is discarded: the in- and out-buffers are
+    /// cleared atomically with the stream swap, so bytes from the old connection can never be
+    /// mixed with bytes from the new one.
+    ///
+    /// This blocks the calling thread for up to `timeout` (or forever, with `timeout: None`).
+    /// `get_next_message`/`send_message` call this with their own timeout when auto-reconnect is
+    /// enabled. The non-blocking API never calls this on its own: `try_get_next_message` instead
+    /// returns the broken-connection error to the caller, who can call `reconnect` explicitly
+    /// (accepting the block, e.g. off the event-loop thread) once they're ready to.
+    pub fn reconnect(&mut self, timeout: Option<time::Duration>) -> Result<()> {
+        let start_time = time::Instant::now();
+        let mut stream = connect_transport(&self.addr, calc_timeout_left(&start_time, timeout)?)?;
+        handshake(&mut stream, self.with_unix_fd, &start_time, timeout)?;
+
+        stream.set_nonblocking(self.nonblocking)?;
+        self.stream = stream;
+        self.msg_buf_in.clear();
+        self.msg_buf_out.clear();
+        self.pending_cmsgs.clear();
+        self.out_write_cursor = 0;
+        self.out_pending_fds.clear();
+        self.out_fds_sent = false;
+        self.out_pending_serial = None;
+        self.touch_activity();
+        self.reconnect_generation = self.reconnect_generation.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Whether `err` indicates the peer has gone away and a reconnect might help.
+    fn is_broken_connection(err: &Error) -> bool {
+        match err {
+            Error::IoError(e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::NotConnected
+                    | std::io::ErrorKind::UnexpectedEof
+            ),
+            Error::NixError(e) => matches!(
+                e.as_errno(),
+                Some(nix::errno::Errno::EPIPE) | Some(nix::errno::Errno::ECONNRESET)
+            ),
+            _ => false,
+        }
     }
 
     /// Connect to a unix socket. The default little endian byteorder is used
@@ -287,12 +1018,11 @@ impl Conn {
     }
 
     /// Reads from the source once but takes care that the internal buffer only reaches at maximum max_buffer_size
-    /// so we can process messages separatly and avoid leaking file descriptors to wrong messages
-    fn refill_buffer(
-        &mut self,
-        max_buffer_size: usize,
-        timeout: Option<time::Duration>,
-    ) -> Result<Vec<ControlMessageOwned>> {
+    /// so we can process messages separatly and avoid leaking file descriptors to wrong messages.
+    /// Any fds received are pushed onto `self.pending_cmsgs` rather than returned, so a read that
+    /// spans several non-blocking calls never drops fds collected by an earlier call.
+    fn refill_buffer(&mut self, max_buffer_size: usize, timeout: Option<time::Duration>) -> Result<()> {
+        self.maybe_idle_trim();
         let bytes_to_read = max_buffer_size - self.msg_buf_in.len();
 
         const BUFSIZE: usize = 512;
@@ -302,8 +1032,16 @@ impl Conn {
         let mut cmsgspace = cmsg_space!([RawFd; 10]);
         let flags = MsgFlags::empty();
 
-        let old_timeout = self.stream.read_timeout()?;
-        self.stream.set_read_timeout(timeout)?;
+        // In non-blocking mode, timeout is irrelevant: recvmsg returns immediately with either
+        // data or EAGAIN, which we surface as Error::TimedOut below so callers can treat it the
+        // same way as a blocking-mode timeout.
+        let old_timeout = if self.nonblocking {
+            None
+        } else {
+            let old_timeout = self.stream.read_timeout()?;
+            self.stream.set_read_timeout(timeout)?;
+            old_timeout
+        };
         let msg = recvmsg(
             self.stream.as_raw_fd(),
             &[iovec],
@@ -314,21 +1052,67 @@ impl Conn {
             Some(nix::errno::Errno::EAGAIN) => Error::TimedOut,
             _ => Error::NixError(e),
         })?;
-        let cmsgs = msg.cmsgs().collect();
-        self.stream.set_read_timeout(old_timeout)?;
+        self.pending_cmsgs.extend(msg.cmsgs());
+        if !self.nonblocking {
+            self.stream.set_read_timeout(old_timeout)?;
+        }
+        if msg.bytes == 0 && bytes_to_read > 0 {
+            // The peer closed its end of the socket: recvmsg keeps returning Ok(0) rather than an
+            // error, so without this check the header-read loop in get_next_message_once would just spin rereading
+            // nothing forever. Report it the same way a reset/broken-pipe read error is reported,
+            // so is_broken_connection (and therefore auto-reconnect) sees it too.
+            return Err(Error::IoError(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "peer closed the connection",
+            )));
+        }
+        self.record_read(msg.bytes);
         self.msg_buf_in
             .extend(&mut tmpbuf[..msg.bytes].iter().copied());
-        Ok(cmsgs)
+        Ok(())
     }
 
-    /// Blocks until a message has been read from the conn or the timeout has been reached
+    /// Blocks until a message has been read from the conn or the timeout has been reached.
+    ///
+    /// If `set_auto_reconnect(true)` was called and the peer has gone away, this transparently
+    /// reconnects and retries the read once before giving up.
     pub fn get_next_message(
         &mut self,
         timeout: Option<time::Duration>,
+    ) -> Result<message::Message> {
+        match self.get_next_message_once(timeout) {
+            Err(e) if self.auto_reconnect && Self::is_broken_connection(&e) => {
+                self.reconnect(timeout)?;
+                self.get_next_message_once(timeout)
+            }
+            other => other,
+        }
+    }
+
+    /// Non-blocking variant of `get_next_message` for use with `set_nonblocking(true)` from an
+    /// external event loop. Returns `Async::NotReady` instead of blocking when the socket isn't
+    /// readable yet; bytes read so far stay buffered in `msg_buf_in`/`pending_cmsgs` and are
+    /// picked back up on the next call, so no partial message is ever lost.
+    ///
+    /// Unlike `get_next_message`, this never reconnects on its own even with
+    /// `set_auto_reconnect(true)`: `reconnect` blocks for as long as the new connect + handshake
+    /// takes, which would stall the event loop this method exists to integrate with. A broken
+    /// connection is returned as `Err` so the caller can call `reconnect` explicitly once it's
+    /// ready to block (e.g. off the event-loop thread), with whatever timeout it chooses.
+    pub fn try_get_next_message(&mut self) -> Result<Async<message::Message>> {
+        match self.get_next_message_once(None) {
+            Ok(msg) => Ok(Async::Ready(msg)),
+            Err(Error::TimedOut) => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn get_next_message_once(
+        &mut self,
+        timeout: Option<time::Duration>,
     ) -> Result<message::Message> {
         // This whole dance around reading exact amounts of bytes is necessary to read messages exactly at their bounds.
         // I think thats necessary so we can later add support for unixfd sending
-        let mut cmsgs = Vec::new();
 
         //calc timeout in reference to this point in time
         let start_time = time::Instant::now();
@@ -339,21 +1123,27 @@ impl Conn {
                 Err(unmarshal::Error::NotEnoughBytes) => {}
                 Err(e) => return Err(Error::from(e)),
             }
-            let new_cmsgs = self.refill_buffer(
+            self.refill_buffer(
                 unmarshal::HEADER_LEN,
                 calc_timeout_left(&start_time, timeout)?,
             )?;
-            cmsgs.extend(new_cmsgs);
         };
 
-        // read the 4 bytes that tell us how big the header fields are because that info is not included in the header
-        let mut header_fields_len = [0u8; 4];
-        self.stream.read_exact(&mut header_fields_len[..])?;
-        let (_, header_fields_len) =
-            util::parse_u32(&header_fields_len.to_vec(), header.byteorder)?;
-
-        // but push that info into the buffer so the unmarshalling has that info too
-        util::write_u32(header_fields_len, header.byteorder, &mut self.msg_buf_in);
+        // read the 4 bytes that tell us how big the header fields are because that info is not
+        // included in the header. This goes through refill_buffer/msg_buf_in like the header
+        // itself (not a bare `read_exact` off the socket) so a non-blocking call that only gets
+        // 1-3 of these bytes keeps them buffered instead of losing them, and so it correctly
+        // reports `Async::NotReady`/`Error::TimedOut` instead of a raw `WouldBlock`/`TimedOut` io error.
+        while self.msg_buf_in.len() < unmarshal::HEADER_LEN + 4 {
+            self.refill_buffer(
+                unmarshal::HEADER_LEN + 4,
+                calc_timeout_left(&start_time, timeout)?,
+            )?;
+        }
+        let (_, header_fields_len) = util::parse_u32(
+            &self.msg_buf_in[unmarshal::HEADER_LEN..unmarshal::HEADER_LEN + 4].to_vec(),
+            header.byteorder,
+        )?;
 
         let complete_header_size = unmarshal::HEADER_LEN + header_fields_len as usize + 4; // +4 because the length of the header fields does not count
 
@@ -368,9 +1158,7 @@ impl Conn {
             + (header.body_len + header_fields_len + 4) as usize
             + padding_between_header_and_body; // +4 because the length of the header fields does not count
         loop {
-            let new_cmsgs =
-                self.refill_buffer(bytes_needed, calc_timeout_left(&start_time, timeout)?)?;
-            cmsgs.extend(new_cmsgs);
+            self.refill_buffer(bytes_needed, calc_timeout_left(&start_time, timeout)?)?;
             if self.msg_buf_in.len() == bytes_needed {
                 break;
             }
@@ -382,26 +1170,53 @@ impl Conn {
         }
         self.msg_buf_in.clear();
 
-        for cmsg in cmsgs {
+        let mut received_fds = Vec::new();
+        for cmsg in self.pending_cmsgs.drain(..) {
             match cmsg {
                 ControlMessageOwned::ScmRights(fds) => {
-                    msg.raw_fds.extend(fds);
-                }
-                _ => {
-                    // TODO what to do?
-                    println!("Cmsg other than ScmRights: {:?}", cmsg);
+                    received_fds.extend(fds.into_iter().map(OwnedFd::new));
                 }
+                _ => return Err(Error::UnsupportedControlMessage),
             }
         }
+
+        let expected = msg.expected_fd_count();
+        if received_fds.len() != expected as usize {
+            return Err(Error::FdCountMismatch {
+                expected,
+                received: received_fds.len(),
+            });
+        }
+        msg.raw_fds
+            .extend(received_fds.into_iter().map(OwnedFd::into_raw_fd));
         Ok(msg)
     }
 
-    /// send a message over the conn
+    /// send a message over the conn.
+    ///
+    /// If `set_auto_reconnect(true)` was called and the peer has gone away, this transparently
+    /// reconnects and retries the send once before giving up.
     pub fn send_message(
+        &mut self,
+        msg: message::Message,
+        timeout: Option<time::Duration>,
+    ) -> Result<message::Message> {
+        match self.send_message_once(msg.clone(), timeout) {
+            Err(e) if self.auto_reconnect && Self::is_broken_connection(&e) => {
+                self.reconnect(timeout)?;
+                self.send_message_once(msg, timeout)
+            }
+            other => other,
+        }
+    }
+
+    fn send_message_once(
         &mut self,
         mut msg: message::Message,
         timeout: Option<time::Duration>,
     ) -> Result<message::Message> {
+        check_fd_count(&msg, self.stream.supports_fd_passing())?;
+        self.maybe_idle_trim();
         self.msg_buf_out.clear();
         if msg.serial.is_none() {
             msg.serial = Some(self.serial_counter);
@@ -417,19 +1232,135 @@ impl Conn {
         let iov = [IoVec::from_slice(&self.msg_buf_out)];
         let flags = MsgFlags::empty();
 
+        let cmsgs: &[ControlMessage] = if self.stream.supports_fd_passing() {
+            &[ControlMessage::ScmRights(msg.fds_to_send())]
+        } else {
+            &[]
+        };
+
         let old_timeout = self.stream.read_timeout()?;
         self.stream.set_read_timeout(timeout)?;
-        let l = sendmsg(
-            self.stream.as_raw_fd(),
-            &iov,
-            &[ControlMessage::ScmRights(&msg.raw_fds)],
-            flags,
-            None,
-        )?;
+        let send_start = time::Instant::now();
+        let l = sendmsg(self.stream.as_raw_fd(), &iov, cmsgs, flags, None)?;
         self.stream.set_read_timeout(old_timeout)?;
         assert_eq!(l, self.msg_buf_out.len());
+        // This blocking send writes the whole message in one go, so there is nothing left for
+        // try_flush_message to pick up; put the cursor at the end (instead of leaving it at 0)
+        // so trim_buffers doesn't mistake this finished blocking send for a partial non-blocking
+        // write still in flight.
+        self.out_write_cursor = self.msg_buf_out.len();
+        self.record_write(l);
+        self.throttle_send(l, send_start);
         Ok(msg)
     }
+
+    /// If a send rate limit is set, sleeps off however much time this send of `bytes_sent` ran
+    /// ahead of the schedule implied by the limit. A no-op (and no sleep) when unset or when the
+    /// send already took at least as long as the limit demands, so a fast limiter never adds
+    /// latency and a slow send never gets penalized twice. Because each public `Conn` method
+    /// measures its own timeout budget from a fresh `Instant::now()`, a sleep here never eats
+    /// into a subsequent call's timeout.
+    fn throttle_send(&self, bytes_sent: usize, send_start: time::Instant) {
+        if let Some(rate) = self.send_rate_limit {
+            if rate > 0 {
+                let target_elapsed = time::Duration::from_secs_f64(bytes_sent as f64 / rate as f64);
+                let actual_elapsed = send_start.elapsed();
+                if target_elapsed > actual_elapsed {
+                    std::thread::sleep(target_elapsed - actual_elapsed);
+                }
+            }
+        }
+    }
+
+    /// Non-blocking variant of `send_message`. Marshals `msg` and writes as much of it as the
+    /// socket accepts without blocking, returning the number of bytes written. If the message
+    /// doesn't fit in one write, the remainder is buffered internally; call `try_flush_message`
+    /// on subsequent writable events to push out the rest. The `ScmRights` control message
+    /// (if any) is only ever attached to the first write of a given message, so finishing it over
+    /// several `try_flush_message` calls never sends the same fds twice.
+    ///
+    /// Returns `Error::OutputBufferFull` if a previous message hasn't finished flushing yet.
+    ///
+    /// `set_send_rate_limit` is not applied here (or in `try_flush_message`): it sleeps the
+    /// calling thread, which would stall the event loop this method exists to integrate with.
+    pub fn try_send_message(&mut self, mut msg: message::Message) -> Result<usize> {
+        if self.out_write_cursor < self.msg_buf_out.len() {
+            return Err(Error::OutputBufferFull);
+        }
+        check_fd_count(&msg, self.stream.supports_fd_passing())?;
+        self.maybe_idle_trim();
+
+        self.msg_buf_out.clear();
+        if msg.serial.is_none() {
+            msg.serial = Some(self.serial_counter);
+            self.serial_counter += 1;
+        }
+        marshal::marshal(
+            &msg,
+            message::ByteOrder::LittleEndian,
+            &[],
+            &mut self.msg_buf_out,
+        )?;
+
+        self.out_write_cursor = 0;
+        self.out_fds_sent = false;
+        self.out_pending_fds = std::mem::take(&mut msg.raw_fds);
+        self.out_pending_serial = msg.serial;
+
+        self.write_nonblocking_chunk()
+    }
+
+    /// Complete a message previously started by `try_send_message` that didn't fit in one
+    /// write. Returns `Async::Ready(serial)` once fully flushed, `Async::NotReady` if the
+    /// socket would still block.
+    pub fn try_flush_message(&mut self) -> Result<Async<u32>> {
+        if self.out_write_cursor >= self.msg_buf_out.len() {
+            return Ok(Async::Ready(self.out_pending_serial.unwrap_or(0)));
+        }
+        self.write_nonblocking_chunk()?;
+        if self.out_write_cursor >= self.msg_buf_out.len() {
+            Ok(Async::Ready(self.out_pending_serial.unwrap_or(0)))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    /// Write as much of `msg_buf_out[out_write_cursor..]` as fits in one non-blocking `sendmsg`
+    /// call, advancing the cursor. The fds in `out_pending_fds` are only attached the first
+    /// time any bytes of this message are written.
+    fn write_nonblocking_chunk(&mut self) -> Result<usize> {
+        let remaining = &self.msg_buf_out[self.out_write_cursor..];
+        let iov = [IoVec::from_slice(remaining)];
+        let flags = MsgFlags::empty();
+
+        let cmsgs: &[ControlMessage] = if self.out_fds_sent
+            || self.out_pending_fds.is_empty()
+            || !self.stream.supports_fd_passing()
+        {
+            &[]
+        } else {
+            &[ControlMessage::ScmRights(&self.out_pending_fds)]
+        };
+
+        let l = match sendmsg(self.stream.as_raw_fd(), &iov, cmsgs, flags, None) {
+            Ok(l) => l,
+            // Nothing was written (and so no fds sent); the message stays buffered for the next
+            // try_flush_message, which then reports Async::NotReady.
+            Err(e) if e.as_errno() == Some(nix::errno::Errno::EAGAIN) => return Ok(0),
+            Err(e) => return Err(Error::NixError(e)),
+        };
+        self.out_write_cursor += l;
+        self.out_fds_sent = true;
+        Ok(l)
+    }
+}
+
+impl AsRawFd for Conn {
+    /// Exposes the underlying socket so it can be registered with an external event loop
+    /// (mio/epoll) when driving `Conn` in non-blocking mode.
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
 }
 
 /// Convenience function that returns a path to the session bus according to the env var $DBUS_SESSION_BUS_ADDRESS
@@ -451,6 +1382,14 @@ pub fn get_session_bus_path() -> Result<PathBuf> {
     }
 }
 
+/// Parses the full address list from the env var $DBUS_SESSION_BUS_ADDRESS, including `tcp:`,
+/// `nonce-tcp:` and `unix:abstract=` entries that `get_session_bus_path` rejects. Try each
+/// returned address with `Conn::connect_to_bus_addr` in order, as the D-Bus spec requires.
+pub fn get_session_bus_addresses() -> Result<Vec<BusAddress>> {
+    let envvar = std::env::var("DBUS_SESSION_BUS_ADDRESS").map_err(|_| Error::NoAdressFound)?;
+    parse_bus_addresses(&envvar)
+}
+
 /// Convenience function that returns a path to the system bus at /run/dbus/systemd_bus_socket
 pub fn get_system_bus_path() -> Result<PathBuf> {
     let ps = "/run/dbus/system_bus_socket";
@@ -462,6 +1401,24 @@ pub fn get_system_bus_path() -> Result<PathBuf> {
     }
 }
 
+/// Checks that the number of fds a message actually carries matches its `UNIX_FDS` header
+/// field, so a caller who edited one without the other gets a clear error instead of sending
+/// (or believing it received) a mismatched `SCM_RIGHTS` payload.
+fn check_fd_count(msg: &message::Message, supports_fd_passing: bool) -> Result<()> {
+    let expected = msg.expected_fd_count();
+    let actual = msg.fds_to_send().len();
+    if !supports_fd_passing && actual > 0 {
+        return Err(Error::FdPassingUnsupported);
+    }
+    if expected as usize != actual {
+        return Err(Error::FdCountMismatch {
+            expected,
+            received: actual,
+        });
+    }
+    Ok(())
+}
+
 fn calc_timeout_left(
     start_time: &time::Instant,
     timeout: Option<time::Duration>,
@@ -478,3 +1435,117 @@ fn calc_timeout_left(
         None => Ok(None),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_unescape_decodes_percent_escapes() {
+        assert_eq!(url_unescape("abc"), "abc");
+        assert_eq!(url_unescape("%2Fvar%2Frun"), "/var/run");
+        assert_eq!(url_unescape("100%25"), "100%");
+    }
+
+    #[test]
+    fn url_unescape_passes_through_malformed_escapes_without_panicking() {
+        // A stray '%' right before a multi-byte UTF-8 character must not panic by slicing
+        // mid-character; it's just passed through unescaped.
+        assert_eq!(url_unescape("%\u{20ac}"), "%\u{20ac}");
+        // Not enough hex digits left after the '%'.
+        assert_eq!(url_unescape("abc%"), "abc%");
+        assert_eq!(url_unescape("abc%2"), "abc%2");
+        // Non-hex digits after '%'.
+        assert_eq!(url_unescape("%zz"), "%zz");
+    }
+
+    #[test]
+    fn parse_bus_addresses_handles_unix_abstract_and_tcp() {
+        let addrs = parse_bus_addresses(
+            "unix:path=/run/user/1000/bus;unix:abstract=/tmp/dbus-xyz;tcp:host=localhost,port=1234",
+        )
+        .unwrap();
+        assert_eq!(
+            addrs,
+            vec![
+                BusAddress::UnixPath(PathBuf::from("/run/user/1000/bus")),
+                BusAddress::UnixAbstract(b"/tmp/dbus-xyz".to_vec()),
+                BusAddress::Tcp {
+                    host: "localhost".to_owned(),
+                    port: 1234
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_bus_addresses_rejects_unknown_transport() {
+        assert!(matches!(
+            parse_bus_addresses("carrier-pigeon:path=/dev/null"),
+            Err(Error::AddressTypeNotSupported(_))
+        ));
+    }
+
+    #[test]
+    fn parse_bus_addresses_rejects_empty_list() {
+        assert!(matches!(parse_bus_addresses(""), Err(Error::NoAdressFound)));
+    }
+
+    /// A `Conn` wrapping one end of a connected unix socketpair, skipping `connect_transport`/
+    /// `handshake` entirely so buffering invariants can be tested without a real bus to talk to.
+    fn test_conn() -> Conn {
+        let (a, _b) = nix::sys::socket::socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        // Safety: `a` was just created above by socketpair and is owned by nobody else yet.
+        let stream = Transport::Unix(unsafe { UnixStream::from_raw_fd(a) });
+        Conn::assemble(
+            BusAddress::UnixPath(PathBuf::from("/dev/null")),
+            stream,
+            message::ByteOrder::LittleEndian,
+            false,
+        )
+    }
+
+    #[test]
+    fn trim_buffers_leaves_a_partial_message_alone() {
+        let mut conn = test_conn();
+        conn.set_idle_trim(None, unmarshal::HEADER_LEN);
+        conn.msg_buf_in = Vec::with_capacity(4096);
+        conn.msg_buf_in.extend_from_slice(&[0u8; 3]); // a partially-read header
+        conn.trim_buffers();
+        assert!(conn.msg_buf_in.capacity() >= 4096);
+    }
+
+    #[test]
+    fn trim_buffers_shrinks_an_idle_empty_buffer() {
+        let mut conn = test_conn();
+        conn.set_idle_trim(None, unmarshal::HEADER_LEN);
+        conn.msg_buf_in = Vec::with_capacity(4096);
+        conn.trim_buffers();
+        assert!(conn.msg_buf_in.capacity() <= unmarshal::HEADER_LEN);
+    }
+
+    #[test]
+    fn trim_buffers_only_shrinks_msg_buf_out_once_fully_flushed() {
+        let mut conn = test_conn();
+        conn.set_idle_trim(None, unmarshal::HEADER_LEN);
+        conn.msg_buf_out = Vec::with_capacity(4096);
+        conn.msg_buf_out.resize(100, 0);
+
+        // out_write_cursor == 0 < msg_buf_out.len(): a non-blocking write could still be
+        // in flight, so trim_buffers must leave the buffer alone.
+        conn.out_write_cursor = 0;
+        conn.trim_buffers();
+        assert!(conn.msg_buf_out.capacity() >= 4096);
+
+        // out_write_cursor caught up to msg_buf_out.len(): fully flushed, now trimmable.
+        conn.out_write_cursor = conn.msg_buf_out.len();
+        conn.trim_buffers();
+        assert!(conn.msg_buf_out.capacity() <= unmarshal::HEADER_LEN);
+    }
+}